@@ -1,11 +1,13 @@
 use anyhow::Result;
 use irc::client::prelude::*;
-use irc::proto::mode::ChannelMode::Ban;
+use irc::proto::mode::ChannelMode::{Ban, InviteException};
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::Deserialize;
 use std::collections::{HashMap, HashSet};
 
+use crate::mask;
+
 const FOUNDER: &[char; 11] =
     &['A', 'F', 'R', 'e', 'f', 'i', 'o', 'r', 's', 't', 'v'];
 const CRAT: &[char; 7] = &['A', 'f', 'i', 'o', 'r', 't', 'v'];
@@ -13,7 +15,6 @@ const OP: &[char; 5] = &['A', 'i', 'o', 't', 'v'];
 const PLUS_O: &[char; 1] = &['o'];
 const AUTOVOICE: &[char; 2] = &['V', 'v'];
 
-// TODO: set forward to -overflow
 const GLOBAL_BANS: &str = "$j:#wikimedia-bans";
 const LIBERA_STAFF: &str = "*!*@libera/staff/*";
 const LITHARGE: &str = "litharge";
@@ -30,6 +31,44 @@ fn parse_flags(input: &str) -> HashSet<char> {
     set
 }
 
+/// How the `$j:#wikimedia-bans` redirect is configured.
+///
+/// Accepts either a plain `global_bans = true` toggle or a table declaring a
+/// forward channel, e.g. `global_bans = { forward = "#overflow" }`, which
+/// emits a forwarded extban like `$j:#wikimedia-bans$#overflow`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum GlobalBans {
+    Toggle(bool),
+    Forward { forward: String },
+}
+
+impl Default for GlobalBans {
+    fn default() -> Self {
+        GlobalBans::Toggle(false)
+    }
+}
+
+impl GlobalBans {
+    /// Whether the global-ban redirect should be set.
+    fn enabled(&self) -> bool {
+        match self {
+            GlobalBans::Toggle(enabled) => *enabled,
+            GlobalBans::Forward { .. } => true,
+        }
+    }
+
+    /// The desired extban text, with the forward channel appended if any.
+    fn mask(&self) -> String {
+        match self {
+            GlobalBans::Forward { forward } => {
+                format!("{}${}", GLOBAL_BANS, forward)
+            }
+            GlobalBans::Toggle(_) => GLOBAL_BANS.to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Default, Deserialize)]
 pub struct ConfiguredChannel {
     #[serde(default)]
@@ -43,7 +82,10 @@ pub struct ConfiguredChannel {
     #[serde(default)]
     pub autovoice: HashSet<String>,
     #[serde(default)]
-    pub global_bans: bool,
+    pub global_bans: GlobalBans,
+    /// Declarative ban list; masks here are added and redundant entries removed
+    #[serde(default)]
+    pub bans: HashSet<String>,
     /// Gives Libera staff and litharge +o rights
     #[serde(default)]
     pub libera_staff: bool,
@@ -111,6 +153,13 @@ impl ManagedChannel {
         self.flags_done && self.bans_done && self.invexes_done
     }
 
+    /// Whether both the ban and invex lists have been fully received. Unlike
+    /// [`is_done`](Self::is_done) this doesn't require the ChanServ flag list,
+    /// which arrives on a separate channel.
+    pub fn lists_done(&self) -> bool {
+        self.bans_done && self.invexes_done
+    }
+
     pub fn fix_flags(&self, cfg: &ConfiguredChannel) -> Vec<(String, String)> {
         let mut changes: HashMap<String, FlagChange> = HashMap::new();
         for (name, flags) in self.current.iter() {
@@ -186,12 +235,49 @@ impl ManagedChannel {
 
     pub fn fix_modes(&self, cfg: &ConfiguredChannel) -> Vec<Mode<ChannelMode>> {
         let mut cmds = vec![];
-        if cfg.global_bans && !self.bans.contains(GLOBAL_BANS) {
-            cmds.push(Mode::Plus(Ban, Some(GLOBAL_BANS.to_string())));
-        } else if !cfg.global_bans && self.bans.contains(GLOBAL_BANS) {
-            cmds.push(Mode::Minus(Ban, Some(GLOBAL_BANS.to_string())));
+        // The global-ban redirect is a managed entry: force it present when
+        // enabled (dropping any stale variant) and absent otherwise.
+        if cfg.global_bans.enabled() {
+            let want = cfg.global_bans.mask();
+            if !self.bans.contains(&want) {
+                cmds.push(Mode::Plus(Ban, Some(want.clone())));
+            }
+            for ban in &self.bans {
+                if ban.starts_with(GLOBAL_BANS) && ban != &want {
+                    cmds.push(Mode::Minus(Ban, Some(ban.to_string())));
+                }
+            }
+        } else {
+            for ban in &self.bans {
+                if ban.starts_with(GLOBAL_BANS) {
+                    cmds.push(Mode::Minus(Ban, Some(ban.to_string())));
+                }
+            }
         }
 
+        // Reconcile the declarative ban and invex lists. The global-ban
+        // redirect is managed above, so keep it out of the existing set here:
+        // otherwise a broad configured ban (e.g. `*!*@*`) matches its literal
+        // text and the two paths fight over the same entry.
+        let existing_bans: HashSet<String> = self
+            .bans
+            .iter()
+            .filter(|b| !b.starts_with(GLOBAL_BANS))
+            .cloned()
+            .collect();
+        let (add, remove) = mask::reconcile(&cfg.bans, &existing_bans);
+        cmds.extend(add.into_iter().map(|m| Mode::Plus(Ban, Some(m))));
+        cmds.extend(remove.into_iter().map(|m| Mode::Minus(Ban, Some(m))));
+        let (add, remove) = mask::reconcile(&cfg.invexes, &self.invexes);
+        cmds.extend(
+            add.into_iter().map(|m| Mode::Plus(InviteException, Some(m))),
+        );
+        cmds.extend(
+            remove
+                .into_iter()
+                .map(|m| Mode::Minus(InviteException, Some(m))),
+        );
+
         cmds
     }
 
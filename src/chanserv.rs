@@ -1,74 +1,97 @@
 //! Interact with ChanServ
 use crate::LockedState;
 use irc::client::Client;
+use std::collections::VecDeque;
 use std::sync::Arc;
 use tokio::sync::mpsc::Receiver;
-use tokio::time::{interval, Duration};
+use tokio::sync::oneshot;
 
 /// Messages that go over the ChanServ channel
-#[derive(Clone, Debug)]
 pub enum Message {
-    /// /cs flags <#channel>
-    Flags(String),
+    /// Request ChanServ's `/cs flags <#channel>` list. The promise is fulfilled
+    /// once the full list has been collected into `BotState`.
+    Flags {
+        channel: String,
+        done: oneshot::Sender<()>,
+    },
     /// A NOTICE from ChanServ
     Notice(String),
 }
 
-/// Listen to messages on the ChanServ channel
+type Request = (String, oneshot::Sender<()>);
+
+/// The ChanServ actor.
+///
+/// ChanServ answers one `flags` query at a time with a stream of NOTICEs
+/// terminated by an `End of ...` line, so requests are serviced from an
+/// in-order FIFO queue: we send the command for the request at the head,
+/// accumulate its notices, and on the terminating line fulfill that request's
+/// promise and move on to the next.
 pub async fn listen(
     rx: &mut Receiver<Message>,
     state: LockedState,
     client: Arc<Client>,
 ) {
-    while let Some(notice) = rx.recv().await {
-        match notice {
-            Message::Flags(channel) => {
-                if state.read().await.chanserv.is_some() {
-                    // Someone else is reading from chanserv, please wait
-                    let mut interval = interval(Duration::from_millis(200));
-                    loop {
-                        if state.read().await.chanserv.is_none() {
-                            break;
-                        }
-                        interval.tick().await;
-                    }
-                }
-                {
-                    let mut w = state.write().await;
-                    w.chanserv = Some(Message::Flags(channel.to_string()));
+    // Requests waiting for their turn
+    let mut queue: VecDeque<Request> = VecDeque::new();
+    // The request whose notices we're currently collecting
+    let mut current: Option<Request> = None;
+
+    while let Some(message) = rx.recv().await {
+        match message {
+            Message::Flags { channel, done } => {
+                queue.push_back((channel, done));
+                if current.is_none() {
+                    current = start_next(&mut queue, &client);
                 }
-                client
-                    .send_privmsg("ChanServ", format!("flags {}", &channel))
-                    .unwrap();
-                continue;
             }
             Message::Notice(notice) => {
-                // Clone instead of locking since we need to get the
-                // write lock inside to clear it
-                let looking = state.read().await.chanserv.clone();
                 if notice.starts_with("--------------")
                     || notice.starts_with("Entry    Nickname/Host")
                 {
                     continue;
                 }
-                if let Some(Message::Flags(channel)) = &looking {
-                    if notice.starts_with("End of") {
-                        let mut w = state.write().await;
-                        w.channels.get_mut(channel).unwrap().flags_done = true;
-                        w.chanserv = None;
-                    } else {
-                        let mut w = state.write().await;
-                        let managed =
-                            w.channels.entry(channel.to_string()).or_default();
-                        match managed.add_flags_from_chanserv(&notice) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                dbg!(e);
-                            }
-                        }
+                let channel = match &current {
+                    Some((channel, _)) => channel.clone(),
+                    // Nothing in flight, so this notice isn't ours
+                    None => continue,
+                };
+                if notice.starts_with("End of") {
+                    // Mark flags complete before fulfilling the promise so
+                    // callers and `is_channel_done` observe a consistent state.
+                    state
+                        .write()
+                        .await
+                        .channels
+                        .entry(channel.clone())
+                        .or_default()
+                        .flags_done = true;
+                    if let Some((_, done)) = current.take() {
+                        // Ignore send errors: the caller may have timed out
+                        let _ = done.send(());
+                    }
+                    current = start_next(&mut queue, &client);
+                } else {
+                    let mut w = state.write().await;
+                    let managed = w.channels.entry(channel).or_default();
+                    if let Err(e) = managed.add_flags_from_chanserv(&notice) {
+                        dbg!(e);
                     }
                 }
             }
-        };
+        }
     }
 }
+
+/// Pop the next queued request, ask ChanServ for its flags, and return it as
+/// the in-progress request.
+fn start_next(
+    queue: &mut VecDeque<Request>,
+    client: &Arc<Client>,
+) -> Option<Request> {
+    let (channel, done) = queue.pop_front()?;
+    client
+        .send_privmsg("ChanServ", format!("flags {}", &channel))
+        .unwrap();
+    Some((channel, done))
+}
@@ -1,8 +1,12 @@
 use anyhow::{anyhow, Result};
+use futures_util::future::{BoxFuture, FutureExt, Shared};
 use irc::client::prelude::*;
-use std::sync::Arc;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex};
 use tokio::sync::mpsc::UnboundedSender;
-use tokio::time::{interval, sleep, Duration};
+use tokio::sync::{oneshot, Mutex};
+use tokio::time::{sleep, timeout, Duration, Instant};
 
 use crate::chanserv;
 use crate::config::TrustLevel;
@@ -11,6 +15,98 @@ use crate::{channel::ManagedChannel, git, is_trusted, LockedState};
 // FIXME: don't hardcode
 const PULL_CHANNEL: &str = "#wikimedia-ops";
 
+/// Minimum delay between successive lines sent to the same target, to stay
+/// clear of the server's flood limits.
+const SEND_DELAY: Duration = Duration::from_secs(2);
+
+/// A running sync shared across every caller that asked for the same channel.
+/// The error is carried as a `String` so the future's output is `Clone`.
+type SyncFuture = Shared<BoxFuture<'static, Result<(), String>>>;
+
+lazy_static! {
+    /// Per-channel send clocks so each target is throttled independently and
+    /// concurrent syncs on different channels don't serialize.
+    static ref SEND_CLOCKS: StdMutex<HashMap<String, Arc<Mutex<Option<Instant>>>>> =
+        StdMutex::new(HashMap::new());
+    /// In-flight syncs keyed by `(channel, dry_run)`, so a second request
+    /// coalesces onto the running one only when it wants the same thing: a
+    /// live `!issync` must never join an in-flight `--dry-run` preview (and so
+    /// apply nothing), nor vice versa.
+    static ref PENDING_SYNCS: StdMutex<HashMap<(String, bool), SyncFuture>> =
+        StdMutex::new(HashMap::new());
+}
+
+/// The throttle clock for a single channel, shared across concurrent callers.
+fn clock_for(channel: &str) -> Arc<Mutex<Option<Instant>>> {
+    let mut clocks = SEND_CLOCKS.lock().unwrap();
+    clocks.entry(channel.to_string()).or_default().clone()
+}
+
+/// Wait until at least `SEND_DELAY` has elapsed since the last line, then stamp
+/// the clock for the next caller.
+async fn throttle(last: &mut Option<Instant>) {
+    if let Some(prev) = *last {
+        let elapsed = prev.elapsed();
+        if elapsed < SEND_DELAY {
+            sleep(SEND_DELAY - elapsed).await;
+        }
+    }
+    *last = Some(Instant::now());
+}
+
+fn format_modes(modes: &[Mode<ChannelMode>]) -> String {
+    modes
+        .iter()
+        .map(|mode| mode.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Send channel mode changes, coalesced into combined MODE lines that never
+/// exceed the server's `MODES=` limit and throttled per channel.
+async fn send_modes(
+    client: &Client,
+    state: &LockedState,
+    channel: &str,
+    modes: &[Mode<ChannelMode>],
+) -> Result<()> {
+    let limit = state.read().await.mode_limit().max(1);
+    let clock = clock_for(channel);
+    let mut last = clock.lock().await;
+    for chunk in modes.chunks(limit) {
+        throttle(&mut last).await;
+        client.send_mode(channel, chunk)?;
+        client.send_privmsg(
+            channel,
+            format!("Set /mode {} {}", channel, format_modes(chunk)),
+        )?;
+    }
+    Ok(())
+}
+
+/// Send `/cs flags` changes, throttled per channel. These can't be coalesced
+/// into a single line, so each is its own throttled command.
+async fn send_flags(
+    client: &Client,
+    channel: &str,
+    flag_cmds: &[(String, String)],
+) -> Result<()> {
+    let clock = clock_for(channel);
+    let mut last = clock.lock().await;
+    for (account, flags) in flag_cmds {
+        throttle(&mut last).await;
+        client.send_privmsg(
+            "ChanServ",
+            format!("flags {} {} {}", channel, account, flags),
+        )?;
+        client.send_privmsg(
+            channel,
+            format!("Set /cs flags {} {} {}", channel, account, flags),
+        )?;
+    }
+    Ok(())
+}
+
 /// Respond to `!isspull`, which pulls the config repo
 ///
 /// This command must be used in the pull channel. Once
@@ -20,6 +116,15 @@ pub async fn iss_pull(client: &Arc<Client>, message: &Message) -> Result<()> {
     // Must be run in the pull channel
     must_be_in(message, PULL_CHANNEL)?;
     let changed = git::pull().await?;
+    announce_changes(client, changed).await
+}
+
+/// Announce the channels a pull touched to the pull channel and join any we've
+/// just learned about. Shared by `!isspull` and the push hook.
+pub async fn announce_changes(
+    client: &Arc<Client>,
+    changed: Vec<String>,
+) -> Result<()> {
     if changed.is_empty() {
         client.send_privmsg(PULL_CHANNEL, "There are no pending changes.")?;
         return Ok(());
@@ -74,25 +179,77 @@ pub async fn iss_sync(
     client: &Arc<Client>,
     state: &LockedState,
     chanserv_tx: UnboundedSender<chanserv::Message>,
+    dry_run: bool,
 ) -> Result<()> {
+    let channel = must_be_in_a_channel(message)?;
+    // Coalesce concurrent syncs of the same channel onto one shared future so a
+    // second request joins the running sync and reports the same outcome,
+    // rather than racing it (and panicking on the duplicate channel removal).
+    let key = (channel.clone(), dry_run);
+    let shared = {
+        let mut pending = PENDING_SYNCS.lock().unwrap();
+        match pending.get(&key) {
+            Some(existing) => existing.clone(),
+            None => {
+                let fut = iss_sync_inner(
+                    message.clone(),
+                    client.clone(),
+                    state.clone(),
+                    chanserv_tx,
+                    dry_run,
+                )
+                .map(|result| result.map_err(|e| e.to_string()))
+                .boxed()
+                .shared();
+                pending.insert(key.clone(), fut.clone());
+                fut
+            }
+        }
+    };
+    let result = shared.await;
+    // Drop the registry entry now that this run has finished, but only if it's
+    // still ours: a later request may have replaced it with a fresh future
+    // once this one resolved, and we mustn't evict that.
+    {
+        let mut pending = PENDING_SYNCS.lock().unwrap();
+        if pending.get(&key).map(|f| f.ptr_eq(&shared)).unwrap_or(false) {
+            pending.remove(&key);
+        }
+    }
+    result.map_err(|e| anyhow!(e))
+}
+
+async fn iss_sync_inner(
+    message: Message,
+    client: Arc<Client>,
+    state: LockedState,
+    chanserv_tx: UnboundedSender<chanserv::Message>,
+    dry_run: bool,
+) -> Result<()> {
+    let message = &message;
+    let client = &client;
+    let state = &state;
     let channel = must_be_in_a_channel(message)?;
     let account = crate::extract_account(&message).ok_or_else(|| {
         anyhow!("You don't have permission to update channel settings")
     })?;
-    // First we need to verify the person making the request is a founder
+    // First we need to verify the person making the request is a founder.
+    // Ask the ChanServ actor for the flags and await its completion promise.
+    let (done_tx, done_rx) = oneshot::channel();
     chanserv_tx
-        .send(chanserv::Message::Flags(channel.to_string()))
+        .send(chanserv::Message::Flags {
+            channel: channel.to_string(),
+            done: done_tx,
+        })
         .unwrap();
-    let mut flag_interval = interval(Duration::from_millis(200));
-    loop {
-        if state.read().await.is_flags_done(&channel) {
-            break;
-        }
-        // Wait a bit (but make sure we're not holding the read lock here)
-        flag_interval.tick().await;
+    if !matches!(timeout(Duration::from_secs(5), done_rx).await, Ok(Ok(()))) {
+        return Err(anyhow!(
+            "Timed out waiting for ChanServ flags for {}",
+            channel
+        ));
     }
     // Must be a bot owner or a channel founder
-    if !is_trusted(&state, &message, TrustLevel::Owner).await
+    if !is_trusted(client, &state, &message, TrustLevel::Owner).await
         && !state.read().await.is_founder_on(&channel, &account)
     {
         return Err(anyhow!(
@@ -100,63 +257,114 @@ pub async fn iss_sync(
         ));
     }
     // At this point the person is authorized to sync
-    let managed_channel =
-        load_managed_channel(client, &channel, state, &account, chanserv_tx)
+    let (managed_channel, opped) =
+        load_managed_channel(client, &channel, state, &account, dry_run)
             .await?;
     //dbg!(&managed_channel);
-    sync_channel(&client, state.clone(), &channel, &managed_channel).await?;
-    // de-op, TODO: possible race here if our mode changes haven't taken effect yet
-    client.send_mode(
+    sync_channel(
+        &client,
+        state.clone(),
         &channel,
-        &[Mode::Minus(
-            UserMode::Oper,
-            Some(client.current_nickname().to_string()),
-        )],
-    )?;
+        &managed_channel,
+        dry_run,
+        opped,
+    )
+    .await?;
+    // de-op, but only if we actually opped up (a dry run may have skipped it)
+    if opped {
+        // TODO: possible race here if our mode changes haven't taken effect yet
+        client.send_mode(
+            &channel,
+            &[Mode::Minus(
+                UserMode::Oper,
+                Some(client.current_nickname().to_string()),
+            )],
+        )?;
+    }
     Ok(())
 }
 
+/// Collect a channel's live state, opping up to read the ban/invex lists.
+///
+/// Returns the collected state and whether we are opped. A dry run avoids
+/// taking ops when it doesn't already have them: it can still diff flags (from
+/// ChanServ), so scheduled drift checks work without granting the bot ops, but
+/// the ban/invex lists are left empty and previewed as "not checked".
 async fn load_managed_channel(
     client: &Client,
     channel: &str,
     state: &LockedState,
     requestor: &str,
-    chanserv_tx: UnboundedSender<chanserv::Message>,
-) -> Result<ManagedChannel> {
-    // It's possible we've already loaded flags before getting here, let's check
-    if !state.read().await.is_flags_done(channel) {
-        chanserv_tx
-            .send(chanserv::Message::Flags(channel.to_string()))
-            .unwrap();
-    }
+    dry_run: bool,
+) -> Result<(ManagedChannel, bool)> {
+    // Flags were already collected by the ChanServ actor in `iss_sync`.
     client.send_privmsg(
-        &channel,
-        format!("Syncing {} (requested by {})", channel, &requestor),
+        channel,
+        format!(
+            "{} {} (requested by {})",
+            if dry_run { "Previewing" } else { "Syncing" },
+            channel,
+            requestor
+        ),
     )?;
+    // A dry run that isn't already opped previews flags only, without op-up.
+    if dry_run && !crate::is_opped_in(client, channel) {
+        client.send_privmsg(
+            channel,
+            format!(
+                "Not opped in {}, previewing flag changes only",
+                channel
+            ),
+        )?;
+        let managed = {
+            let mut w = state.write().await;
+            w.channels.remove(channel).unwrap_or_default()
+        };
+        return Ok((managed, false));
+    }
     // Make sure we're op before checking +b and +I
-    crate::wait_for_op(&client, channel).await?;
+    if !crate::wait_for_op(client, channel).await {
+        return Err(anyhow!("Unable to get opped in {}", channel));
+    }
     // TODO: combine these?
     client.send_mode(channel, &[Mode::Plus(ChannelMode::Ban, None)])?;
     client.send_mode(
         channel,
         &[Mode::Plus(ChannelMode::InviteException, None)],
     )?;
-    // Check every 200ms if we're ready to go
-    let mut done_interval = interval(Duration::from_millis(200));
-    loop {
-        if state.read().await.is_channel_done(channel) {
-            break;
+    // Wait for ChanServ/the server to deliver the ban and invex lists. Arm the
+    // notifier before checking so we can't miss the wakeup, then fail the sync
+    // if the lists never arrive.
+    let notify = state.write().await.notifier(channel);
+    let notified = notify.notified();
+    tokio::pin!(notified);
+    // `notified()` doesn't register the waiter until first polled, so arm it
+    // explicitly before the completion check — otherwise a list that finishes
+    // in the gap between the check and the await is lost and we spuriously
+    // time out.
+    notified.as_mut().enable();
+    let lists_done = {
+        let r = state.read().await;
+        r.channels.get(channel).map(|c| c.lists_done()).unwrap_or(false)
+    };
+    if !lists_done {
+        if timeout(Duration::from_secs(5), notified).await.is_err() {
+            return Err(anyhow!(
+                "Timed out waiting for ban/invex lists for {}",
+                channel
+            ));
         }
-        // Wait a bit (but make sure we're not holding the read lock here)
-        done_interval.tick().await;
     }
     let managed_channel = {
         let mut w = state.write().await;
-        // FIXME not fully safe, if another thread gets the write lock
-        // first it could have already removed the channel.
-        w.channels.remove(channel).unwrap()
+        // Coalescing in `iss_sync` allows one in-flight sync per channel and
+        // mode; a concurrent sync of the other mode (live vs dry run) may have
+        // taken this entry first, which is reported as an error below.
+        w.channels
+            .remove(channel)
+            .ok_or_else(|| anyhow!("Lost channel state for {}", channel))?
     };
-    Ok(managed_channel)
+    Ok((managed_channel, true))
 }
 
 /// Do the actual sync step, comparing the live channel
@@ -166,6 +374,8 @@ async fn sync_channel(
     state: LockedState,
     channel: &str,
     managed_channel: &ManagedChannel,
+    dry_run: bool,
+    lists_collected: bool,
 ) -> Result<()> {
     let cfg = match crate::read_channel_config(
         state.read().await.botconfig.channel_config.clone().as_str(),
@@ -187,37 +397,55 @@ async fn sync_channel(
     };
     //dbg!(&managed_channel, &cfg);
     let flag_cmds = managed_channel.fix_flags(&cfg);
-    let mode_cmds = managed_channel.fix_modes(&cfg);
+    // Without the ban/invex lists (a dry run that didn't op up) the mode diff
+    // would be bogus, so only compute it when those lists were collected.
+    let mode_cmds = if lists_collected {
+        managed_channel.fix_modes(&cfg)
+    } else {
+        vec![]
+    };
     if flag_cmds.is_empty() && mode_cmds.is_empty() {
         client.send_privmsg(channel, format!("No updates for {}", channel))?;
         return Ok(());
     }
-    // If we have to change modes, make sure we're opped (already should've happened)
-    if !mode_cmds.is_empty() {
-        crate::wait_for_op(client, channel).await?;
-    }
-    // FIXME: Implement proper ratelimiting, see https://github.com/aatxe/irc/issues/190
-    for (account, flags) in flag_cmds {
-        client.send_privmsg(
-            "ChanServ",
-            format!("flags {} {} {}", channel, account, flags),
-        )?;
-        sleep(Duration::from_secs(1)).await;
+    // Preview mode: report the diff without touching anything
+    if dry_run {
         client.send_privmsg(
             channel,
-            format!("Set /cs flags {} {} {}", channel, account, flags),
+            format!(
+                "Dry run for {}: {} change(s) would be made, none applied",
+                channel,
+                flag_cmds.len() + mode_cmds.len()
+            ),
         )?;
-        sleep(Duration::from_secs(1)).await;
+        for (account, flags) in &flag_cmds {
+            client.send_privmsg(
+                channel,
+                format!("Would /cs flags {} {} {}", channel, account, flags),
+            )?;
+        }
+        for mode in &mode_cmds {
+            client.send_privmsg(
+                channel,
+                format!("Would /mode {} {}", channel, mode),
+            )?;
+        }
+        if !lists_collected {
+            client.send_privmsg(
+                channel,
+                "(ban/invex changes not checked: bot is not opped)",
+            )?;
+        }
+        return Ok(());
     }
-    for mode in mode_cmds {
-        client.send_mode(channel, &[mode.clone()])?;
-        sleep(Duration::from_secs(1)).await;
-        client.send_privmsg(
-            channel,
-            format!("Set /mode {} {}", channel, &mode),
-        )?;
-        sleep(Duration::from_secs(1)).await;
+    // If we have to change modes, make sure we're opped (already should've happened)
+    if !mode_cmds.is_empty() && !crate::wait_for_op(client, channel).await {
+        return Err(anyhow!("Unable to get opped in {}", channel));
     }
+    // Batch and throttle both streams so a busy channel doesn't trip the
+    // server's excess-flood limits.
+    send_flags(client, channel, &flag_cmds).await?;
+    send_modes(client, &state, channel, &mode_cmds).await?;
 
     Ok(())
 }
@@ -20,10 +20,58 @@ pub struct BotConfig {
     /// List of accounts who are trusted
     #[serde(default)]
     pub trusted: Vec<String>,
+    /// SASL authentication, preferred over the NickServ password identify
+    #[serde(default)]
+    pub sasl: Option<SaslConfig>,
     /// Configuration for the `irc` crate
     pub irc: Config,
 }
 
+/// SASL authentication: the `[sasl]` section of `config.toml`
+#[derive(Clone, Deserialize)]
+#[serde(tag = "mechanism", rename_all = "UPPERCASE")]
+pub enum SaslConfig {
+    /// Username and password, encoded as base64 `user\0user\0pass`
+    Plain {
+        username: String,
+        #[serde(default)]
+        password: Option<String>,
+        /// File to read the password from, as with the top-level one
+        #[serde(default)]
+        password_file: Option<String>,
+    },
+    /// CertFP: present a client certificate and send an empty `+` payload
+    External,
+}
+
+impl SaslConfig {
+    /// Mechanism name to send in `AUTHENTICATE <mechanism>`
+    pub fn mechanism(&self) -> &'static str {
+        match self {
+            SaslConfig::Plain { .. } => "PLAIN",
+            SaslConfig::External => "EXTERNAL",
+        }
+    }
+
+    /// The initial response payload: a base64 blob for PLAIN, or `+` for the
+    /// empty EXTERNAL response.
+    pub fn initial_response(&self) -> String {
+        match self {
+            SaslConfig::Plain {
+                username, password, ..
+            } => {
+                let password = password.clone().unwrap_or_default();
+                base64::encode(format!(
+                    "{user}\0{user}\0{pass}",
+                    user = username,
+                    pass = password
+                ))
+            }
+            SaslConfig::External => "+".to_string(),
+        }
+    }
+}
+
 /// Differentation between owners and trusted users
 pub enum TrustLevel {
     Owner,
@@ -41,6 +89,21 @@ impl BotConfig {
                 fs::read_to_string(password_file).await?.trim().to_string(),
             );
         }
+        // Resolve a SASL PLAIN password_file the same way
+        if let Some(SaslConfig::Plain {
+            password,
+            password_file,
+            ..
+        }) = botconfig.sasl.as_mut()
+        {
+            if password.is_none() {
+                if let Some(path) = password_file {
+                    *password = Some(
+                        fs::read_to_string(path).await?.trim().to_string(),
+                    );
+                }
+            }
+        }
         botconfig.irc.version = Some(format!("{}, git: {}", URL, GIT_VERSION));
 
         Ok(botconfig)
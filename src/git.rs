@@ -1,67 +1,87 @@
 use anyhow::{anyhow, Result};
-use tokio::process;
+use git2::build::CheckoutBuilder;
+use git2::Repository;
 
-/// Execute a git command
-async fn git(args: &[&str]) -> Result<String> {
-    println!("Running $ git {}", args.join(" "));
-    let output = process::Command::new("git").args(args).output().await?;
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout)?)
-    } else {
-        Err(anyhow!(
-            "Running git {} failed: {}",
-            args.join(" "),
-            output.status.code().unwrap_or_default()
+/// Turn a changed config path into its `#channel` name, if it is one.
+fn path_to_channel(path: &str) -> Option<String> {
+    if path.starts_with("channels/") && path.ends_with(".toml") {
+        Some(format!(
+            "#{}",
+            path.trim_start_matches("channels/").trim_end_matches(".toml")
         ))
+    } else {
+        None
     }
 }
 
-/// Get the sha1 of the specified ref
-async fn sha1(ref_: &str) -> Result<String> {
-    git(&["rev-parse", ref_])
-        .await
-        .map(|s| s.trim().to_string())
+/// Fetch updates to the config repo and fast-forward the current branch,
+/// returning the channels whose config changed.
+///
+/// `git2` is synchronous, so the work runs on a blocking thread.
+pub async fn pull() -> Result<Vec<String>> {
+    tokio::task::spawn_blocking(pull_blocking).await?
 }
 
-/// What are the changed files between two commits?
-async fn changes(first: &str, second: &str) -> Result<Vec<String>> {
-    let res = tokio::try_join!(sha1(first), sha1(second));
-    let (first_sha1, second_sha1) = match res {
-        Ok((first_sha1, second_sha1)) => (first_sha1, second_sha1),
-        Err(e) => return Err(e),
-    };
-    Ok(git(&["diff", "--name-only", &first_sha1, &second_sha1])
-        .await?
-        .trim()
-        .split('\n')
-        .map(|s| s.to_string())
-        .collect())
-}
+/// The blocking `git2` implementation of [`pull`].
+///
+/// Capturing the exact old and new `Oid`s around the fetch closes the race the
+/// old shell-out had: the diff is computed between precisely the commit we were
+/// on and the commit we fast-forward to, so a merge landing mid-fetch can't be
+/// missed or double-counted.
+fn pull_blocking() -> Result<Vec<String>> {
+    let repo = Repository::open(".")?;
+    let head = repo.head()?;
+    let branch = head
+        .shorthand()
+        .filter(|_| head.is_branch())
+        .ok_or_else(|| anyhow!("refusing to pull a detached HEAD"))?
+        .to_string();
 
-/// Fetch updates to the config repo, identify which are changes
-/// to channels and then actually pull it.
-pub async fn pull() -> Result<Vec<String>> {
-    // Fetch remote updates
-    git(&["fetch"]).await?;
-    // Identify changes to channel configs
-    let changed = changes("HEAD", "origin/master")
-        .await?
-        .iter()
-        // Turn "channels/foo.toml" -> "#foo"
-        .filter_map(|file| {
-            if file.starts_with("channels/") && file.ends_with(".toml") {
-                Some(format!(
-                    "#{}",
-                    file.trim_start_matches("channels/")
-                        .trim_end_matches(".toml")
-                ))
-            } else {
-                None
+    // Old revision: exactly where the branch is right now
+    let old_oid =
+        head.target().ok_or_else(|| anyhow!("HEAD has no target"))?;
+
+    // Fetch the branch and read back the fetched remote-tracking commit. The
+    // explicit mapped refspec makes libgit2 update `refs/remotes/origin/<branch>`
+    // (a bare `<branch>` would only write `FETCH_HEAD`, leaving the line below
+    // reading a stale Oid).
+    let mut remote = repo.find_remote("origin")?;
+    let refspec = format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch);
+    remote.fetch(&[&refspec], None, None)?;
+    let new_oid =
+        repo.refname_to_id(&format!("refs/remotes/origin/{}", branch))?;
+    // Only fast-forwards are applied; a diverged branch is left untouched
+    if new_oid != old_oid && !repo.graph_descendant_of(new_oid, old_oid)? {
+        return Err(anyhow!("{} has diverged from origin, not pulling", branch));
+    }
+
+    // Diff the two trees for the exact set of files that moved the branch
+    let old_tree = repo.find_commit(old_oid)?.tree()?;
+    let new_tree = repo.find_commit(new_oid)?.tree()?;
+    let diff =
+        repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+    let mut changed = vec![];
+    for delta in diff.deltas() {
+        if let Some(path) =
+            delta.new_file().path().and_then(|path| path.to_str())
+        {
+            if let Some(channel) = path_to_channel(path) {
+                changed.push(channel);
             }
-        })
-        .collect();
-    // Now actually pull the repo!
-    // TODO: race condition if a commit is merged between fetch and pull?
-    git(&["pull"]).await?;
+        }
+    }
+
+    // Fast-forward the working branch to the fetched commit
+    if new_oid != old_oid {
+        let object = repo.find_object(new_oid, None)?;
+        let mut checkout = CheckoutBuilder::new();
+        checkout.safe();
+        repo.checkout_tree(&object, Some(&mut checkout))?;
+        let reference = format!("refs/heads/{}", branch);
+        repo.find_reference(&reference)?
+            .set_target(new_oid, "ircservserv: fast-forward to origin")?;
+        repo.set_head(&reference)?;
+    }
+
     Ok(changed)
 }
@@ -0,0 +1,51 @@
+//! Push-triggered auto-sync over a Unix domain socket.
+//!
+//! A server-side `post-receive` hook pipes the pushed ref data into
+//! `nc -U "$ISS_HOOK_SOCKET"`; each notification makes us pull the config repo
+//! and announce the changed channels exactly as `!isspull` would, so a merge
+//! lands in the live config the moment it's pushed.
+use crate::{command, git};
+use anyhow::Result;
+use irc::client::Client;
+use log::{debug, warn};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::net::UnixListener;
+
+/// Environment variable naming the socket path to bind
+const SOCKET_ENV: &str = "ISS_HOOK_SOCKET";
+
+/// Bind the hook socket (if `ISS_HOOK_SOCKET` is set) and service push
+/// notifications until the listener dies.
+pub async fn listen(client: Arc<Client>) -> Result<()> {
+    let path = match std::env::var(SOCKET_ENV) {
+        Ok(path) => path,
+        Err(_) => {
+            debug!("{} not set, push hook disabled", SOCKET_ENV);
+            return Ok(());
+        }
+    };
+    // Clean up a stale socket left by a previous run
+    let _ = tokio::fs::remove_file(&path).await;
+    let listener = UnixListener::bind(&path)?;
+    debug!("Listening for push hooks on {}", path);
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        // The notification body is short and advisory; just drain it
+        let mut buf = String::new();
+        if let Err(e) = stream.read_to_string(&mut buf).await {
+            warn!("Error reading from hook socket: {}", e);
+            continue;
+        }
+        debug!("Push hook fired: {}", buf.trim());
+        match git::pull().await {
+            Ok(changed) => {
+                if let Err(e) = command::announce_changes(&client, changed).await
+                {
+                    warn!("Error announcing pulled changes: {}", e);
+                }
+            }
+            Err(e) => warn!("Error pulling config repo: {}", e),
+        }
+    }
+}
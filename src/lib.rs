@@ -5,14 +5,19 @@ use log::debug;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::fs;
-use tokio::sync::RwLock;
-use tokio::time::{interval, timeout, Duration};
+use tokio::sync::{oneshot, Notify, RwLock};
+use tokio::time::{interval, timeout, Duration, Instant};
+
+/// How long a resolved WHOIS account mapping is trusted before re-querying
+const WHOIS_TTL: Duration = Duration::from_secs(60);
 
 pub mod channel;
 pub mod chanserv;
 pub mod command;
 pub mod config;
 pub mod git;
+pub mod hook;
+pub mod mask;
 
 pub type LockedState = Arc<RwLock<BotState>>;
 
@@ -21,10 +26,16 @@ use config::TrustLevel;
 
 #[derive(Default)]
 pub struct BotState {
-    /// What we're currently PMing ChanServ for
-    pub chanserv: Option<chanserv::Message>,
     /// State of channels we're currently looking up
     pub channels: HashMap<String, channel::ManagedChannel>,
+    /// Server's advertised `MODES=` limit from ISUPPORT (005), if any
+    pub mode_limit: Option<usize>,
+    /// Fires when all expected lists for a channel have arrived, keyed by name
+    pub notifiers: HashMap<String, Arc<Notify>>,
+    /// In-flight WHOIS account lookups, keyed by lowercased nick
+    pub whois: HashMap<String, Vec<oneshot::Sender<Option<String>>>>,
+    /// Recently resolved nick→account mappings, with the time they were learnt
+    pub whois_cache: HashMap<String, (Option<String>, Instant)>,
     pub botconfig: config::BotConfig,
 }
 
@@ -45,6 +56,17 @@ impl BotState {
         }
     }
 
+    /// The completion notifier for a channel, created on first use.
+    pub fn notifier(&mut self, channel: &str) -> Arc<Notify> {
+        self.notifiers.entry(channel.to_string()).or_default().clone()
+    }
+
+    /// How many mode changes the server accepts per MODE line, defaulting to
+    /// 4 when ISUPPORT didn't advertise a `MODES=` token.
+    pub fn mode_limit(&self) -> usize {
+        self.mode_limit.unwrap_or(4)
+    }
+
     /// Whether the given username is a founder.
     /// NOTE: you need to check that flags_done is true first
     pub fn is_founder_on(&self, channel: &str, username: &str) -> bool {
@@ -110,7 +132,7 @@ async fn read_channel_config(
     )?)
 }
 
-fn is_opped_in(client: &Client, channel: &str) -> bool {
+pub(crate) fn is_opped_in(client: &Client, channel: &str) -> bool {
     if let Some(users) = client.list_users(channel) {
         for user in users {
             if user.get_nickname() == client.current_nickname() {
@@ -123,6 +145,42 @@ fn is_opped_in(client: &Client, channel: &str) -> bool {
     false
 }
 
+/// Resolve an account via a WHOIS round-trip, for when the `account-tag`
+/// wasn't attached. Sends `WHOIS <nick>` and waits (up to 5s) for the
+/// `RPL_WHOISACCOUNT` (330) numeric handled in `main`.
+async fn whois_account(
+    client: &Client,
+    state: &LockedState,
+    nick: &str,
+) -> Option<String> {
+    let key = nick.to_lowercase();
+    // Serve from the cache so repeated commands don't spam WHOIS
+    {
+        let r = state.read().await;
+        if let Some((account, at)) = r.whois_cache.get(&key) {
+            if at.elapsed() < WHOIS_TTL {
+                return account.clone();
+            }
+        }
+    }
+    let (tx, rx) = oneshot::channel();
+    {
+        let mut w = state.write().await;
+        w.whois.entry(key.clone()).or_default().push(tx);
+    }
+    client.send(Command::WHOIS(None, nick.to_string())).ok()?;
+    let account = match timeout(Duration::from_secs(5), rx).await {
+        Ok(Ok(account)) => account,
+        _ => None,
+    };
+    state
+        .write()
+        .await
+        .whois_cache
+        .insert(key, (account.clone(), Instant::now()));
+    account
+}
+
 /// Given a message, extract the account of the sender
 /// using the "account-tags" IRCv3 capability
 pub fn extract_account(message: &Message) -> Option<String> {
@@ -140,13 +198,24 @@ pub fn extract_account(message: &Message) -> Option<String> {
 }
 
 /// Whether the given message was sent from someone who
-/// is in our configured owners or trusted lists
+/// is in our configured owners or trusted lists.
+///
+/// Falls back to a WHOIS lookup when the message lacks an `account` tag, so
+/// trusted users aren't silently denied just because the tag is missing.
 pub async fn is_trusted(
+    client: &Client,
     state: &LockedState,
     message: &Message,
     level: TrustLevel,
 ) -> bool {
-    if let Some(account) = extract_account(message) {
+    let account = match extract_account(message) {
+        Some(account) => Some(account),
+        None => match message.source_nickname() {
+            Some(nick) => whois_account(client, state, nick).await,
+            None => None,
+        },
+    };
+    if let Some(account) = account {
         let list = match level {
             TrustLevel::Owner => state.read().await.botconfig.owners.clone(),
             TrustLevel::Trusted => state.read().await.botconfig.trusted.clone(),
@@ -2,8 +2,11 @@ use anyhow::Result;
 use futures_util::StreamExt;
 use irc::client::prelude::*;
 use irc::proto::caps::Capability;
+use irc::proto::CapSubCommand;
 use irc::proto::response::Response::{
-    RPL_BANLIST, RPL_ENDOFBANLIST, RPL_ENDOFINVITELIST, RPL_INVITELIST,
+    ERR_NOSUCHNICK, ERR_SASLFAIL, RPL_BANLIST, RPL_ENDOFBANLIST,
+    RPL_ENDOFINVITELIST, RPL_ENDOFWHOIS, RPL_INVITELIST, RPL_ISUPPORT,
+    RPL_SASLSUCCESS, RPL_WHOISACCOUNT,
 };
 use log::debug;
 use std::sync::Arc;
@@ -11,8 +14,8 @@ use tokio::sync::{mpsc, RwLock};
 
 use ircservserv::{
     chanserv, command,
-    config::{BotConfig, TrustLevel},
-    extract_account, is_trusted, BotState, LockedState,
+    config::{BotConfig, SaslConfig, TrustLevel},
+    extract_account, hook, is_trusted, BotState, LockedState,
 };
 
 fn is_from(message: &Message, name: &str) -> bool {
@@ -23,6 +26,47 @@ fn is_from(message: &Message, name: &str) -> bool {
     }
 }
 
+/// Drive the SASL `AUTHENTICATE` handshake after the `sasl` capability is
+/// ACKed. Registration is held off until the server reports success; on
+/// failure the bot quits rather than connecting unauthenticated.
+async fn handle_sasl(message: &Message, client: &Client, sasl: &SaslConfig) {
+    match &message.command {
+        Command::CAP(_, sub, _, params) => {
+            let has_sasl = params
+                .as_deref()
+                .map(|p| p.split(' ').any(|c| c == "sasl"))
+                .unwrap_or(false);
+            if sub == &CapSubCommand::ACK && has_sasl {
+                client
+                    .send(Command::AUTHENTICATE(sasl.mechanism().to_string()))
+                    .unwrap();
+            }
+        }
+        Command::AUTHENTICATE(data) => {
+            // Server is ready for our response to the empty challenge
+            if data == "+" {
+                client
+                    .send(Command::AUTHENTICATE(sasl.initial_response()))
+                    .unwrap();
+            }
+        }
+        Command::Response(resp, _) => {
+            if resp == &RPL_SASLSUCCESS {
+                debug!("SASL authentication succeeded");
+                // Authentication is done, so finish registration: `identify`
+                // sends NICK/USER and ends capability negotiation.
+                client.identify().unwrap();
+            } else if resp == &ERR_SASLFAIL {
+                // Don't fall through to an unauthenticated connection; abort.
+                debug!("SASL authentication failed, quitting");
+                client.send_quit("SASL authentication failed").unwrap();
+                std::process::exit(1);
+            }
+        }
+        _ => {}
+    }
+}
+
 async fn handle_response(resp: &Response, data: &[String], state: LockedState) {
     if resp == &RPL_BANLIST {
         let mut w = state.write().await;
@@ -30,17 +74,60 @@ async fn handle_response(resp: &Response, data: &[String], state: LockedState) {
         managed.bans.insert(data[2].to_string());
     } else if resp == &RPL_ENDOFBANLIST {
         let mut w = state.write().await;
-        w.channels.entry(data[1].to_string()).or_default().bans_done = true;
+        let channel = data[1].to_string();
+        w.channels.entry(channel.clone()).or_default().bans_done = true;
+        // The flag list comes over a separate channel, so wake waiters as soon
+        // as both the ban and invex lists are in.
+        let lists_done = w
+            .channels
+            .get(&channel)
+            .map(|c| c.lists_done())
+            .unwrap_or(false);
+        if lists_done {
+            w.notifier(&channel).notify_waiters();
+        }
     } else if resp == &RPL_INVITELIST {
         let mut w = state.write().await;
         let managed = w.channels.entry(data[1].to_string()).or_default();
         managed.invexes.insert(data[2].to_string());
     } else if resp == &RPL_ENDOFINVITELIST {
         let mut w = state.write().await;
-        w.channels
-            .entry(data[1].to_string())
-            .or_default()
-            .invexes_done = true;
+        let channel = data[1].to_string();
+        w.channels.entry(channel.clone()).or_default().invexes_done = true;
+        let lists_done = w
+            .channels
+            .get(&channel)
+            .map(|c| c.lists_done())
+            .unwrap_or(false);
+        if lists_done {
+            w.notifier(&channel).notify_waiters();
+        }
+    } else if resp == &RPL_WHOISACCOUNT {
+        // <me> <nick> <account> :is logged in as
+        let mut w = state.write().await;
+        if let Some(waiters) = w.whois.remove(&data[1].to_lowercase()) {
+            for tx in waiters {
+                let _ = tx.send(Some(data[2].to_string()));
+            }
+        }
+    } else if resp == &RPL_ENDOFWHOIS || resp == &ERR_NOSUCHNICK {
+        // No account numeric arrived (or no such nick), so treat the target as
+        // unauthenticated and finish the lookup.
+        let mut w = state.write().await;
+        if let Some(waiters) = w.whois.remove(&data[1].to_lowercase()) {
+            for tx in waiters {
+                let _ = tx.send(None);
+            }
+        }
+    } else if resp == &RPL_ISUPPORT {
+        // e.g. MODES=4 among the supported-parameter tokens
+        for token in data {
+            if let Some(value) = token.strip_prefix("MODES=") {
+                if let Ok(limit) = value.parse::<usize>() {
+                    state.write().await.mode_limit = Some(limit);
+                }
+            }
+        }
     }
 }
 
@@ -48,6 +135,9 @@ async fn handle_response(resp: &Response, data: &[String], state: LockedState) {
 async fn main() -> Result<()> {
     env_logger::init();
     let botconfig = BotConfig::load("config.toml").await?;
+    // Pulled out before `botconfig` is moved into the shared state, so the
+    // message loop can drive the SASL handshake.
+    let sasl = botconfig.sasl.clone();
     let mut orig_client = Client::from_config(botconfig.irc.clone()).await?;
     let mut stream = orig_client.stream()?;
     // Now that we've got a mutable stream, wrap it in Arc<> for thread-safe read access
@@ -63,8 +153,18 @@ async fn main() -> Result<()> {
     let (chanserv_tx, mut chanserv_rx) =
         mpsc::channel::<chanserv::Message>(128);
 
-    client.send_cap_req(&[Capability::MultiPrefix, Capability::AccountTag])?;
-    client.identify()?;
+    let mut caps = vec![Capability::MultiPrefix, Capability::AccountTag];
+    if sasl.is_some() {
+        // Negotiate `sasl` so we can authenticate before CAP END
+        caps.push(Capability::Sasl);
+    }
+    client.send_cap_req(&caps)?;
+    // With SASL we must authenticate before ending capability negotiation, so
+    // registration (`identify`, which also sends `CAP END`) is deferred until
+    // the handshake succeeds in `handle_sasl`. Without SASL, register now.
+    if sasl.is_none() {
+        client.identify()?;
+    }
 
     let state = bot_state.clone();
     let client_cs = client.clone();
@@ -72,11 +172,23 @@ async fn main() -> Result<()> {
         chanserv::listen(&mut chanserv_rx, state, client_cs).await;
     });
 
+    // Listen for push notifications from the config repo's post-receive hook
+    let client_hook = client.clone();
+    let hook_processor = tokio::spawn(async move {
+        if let Err(e) = hook::listen(client_hook).await {
+            debug!("Push hook listener stopped: {}", e);
+        }
+    });
+
     let state = bot_state.clone();
     let client = client.clone();
+    let sasl_cfg = sasl;
     let processor = tokio::spawn(async move {
         while let Some(message) = rx.recv().await {
             //dbg!(&message);
+            if let Some(sasl) = &sasl_cfg {
+                handle_sasl(&message, &client, sasl).await;
+            }
             match &message.command {
                 Command::NOTICE(_, notice) => {
                     if is_from(&message, "ChanServ") {
@@ -89,8 +201,13 @@ async fn main() -> Result<()> {
                 }
                 Command::PRIVMSG(_, privmsg) => {
                     if privmsg == "!isspull" {
-                        if !is_trusted(&state, &message, TrustLevel::Trusted)
-                            .await
+                        if !is_trusted(
+                            &client,
+                            &state,
+                            &message,
+                            TrustLevel::Trusted,
+                        )
+                        .await
                         {
                             // Silently ignore
                             continue;
@@ -103,9 +220,16 @@ async fn main() -> Result<()> {
                                 command::iss_pull(&client, &target).await;
                             });
                         }
-                    } else if privmsg == "!issync" {
+                    } else if privmsg == "!issync"
+                        || privmsg == "!issync --dry-run"
+                        || privmsg == "!issync --dry"
+                        || privmsg == "!issdiff"
+                    {
+                        // A dry run previews the diff without applying it
+                        let dry_run = privmsg != "!issync";
                         debug!(
-                            "Received !issync for {} from {}",
+                            "Received {} for {} from {}",
+                            privmsg,
                             message.response_target().unwrap_or("unknown"),
                             extract_account(&message)
                                 .unwrap_or_else(|| "unknown".to_string())
@@ -120,6 +244,7 @@ async fn main() -> Result<()> {
                                 &client,
                                 &state,
                                 chanserv_tx,
+                                dry_run,
                             )
                             .await;
                         });
@@ -139,6 +264,7 @@ async fn main() -> Result<()> {
 
     processor.await?;
     chanserv_processor.await?;
+    hook_processor.await?;
 
     Ok(())
 }
@@ -0,0 +1,183 @@
+//! Host-mask parsing and glob matching for bans and invite exceptions
+use std::collections::HashSet;
+
+/// An IRC host-mask, split into `nick!user@host`.
+///
+/// Libera extban forms like `*!*@libera/staff/*` parse as normal since they
+/// still contain `!` and `@`; anything missing a component is treated as `*`
+/// so a bare cloak matches everything in that position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostMask {
+    nick: String,
+    user: String,
+    host: String,
+}
+
+/// Glob match where `*` matches zero-or-more characters and `?` matches
+/// exactly one. Comparison is on the characters as given; callers lowercase
+/// beforehand when a case-insensitive match is wanted.
+fn glob(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // Position of the last `*` in the pattern and where it started matching,
+    // so we can backtrack and let it consume one more character.
+    let mut star: Option<usize> = None;
+    let mut mark = 0;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some(pi);
+            mark = ti;
+            pi += 1;
+        } else if let Some(s) = star {
+            pi = s + 1;
+            mark += 1;
+            ti = mark;
+        } else {
+            return false;
+        }
+    }
+    while pi < p.len() && p[pi] == '*' {
+        pi += 1;
+    }
+    pi == p.len()
+}
+
+impl HostMask {
+    /// Split a mask into `(nick, user, host)` on `!` and `@`, treating empty
+    /// components as `*`.
+    pub fn parse(mask: &str) -> Self {
+        let (nick, rest) = match mask.split_once('!') {
+            Some((nick, rest)) => (nick, rest),
+            None => ("", mask),
+        };
+        let (user, host) = match rest.split_once('@') {
+            Some((user, host)) => (user, host),
+            None => ("", rest),
+        };
+        let star = |s: &str| {
+            if s.is_empty() {
+                "*".to_string()
+            } else {
+                s.to_string()
+            }
+        };
+        Self {
+            nick: star(nick),
+            user: star(user),
+            host: star(host),
+        }
+    }
+
+    /// Whether this (configured) mask covers `other`, i.e. matches its literal
+    /// text under the glob algorithm. The host segment is matched
+    /// case-insensitively. A covered mask is redundant.
+    pub fn covers(&self, other: &HostMask) -> bool {
+        glob(&self.nick, &other.nick)
+            && glob(&self.user, &other.user)
+            && glob(&self.host.to_lowercase(), &other.host.to_lowercase())
+    }
+}
+
+/// Reconcile an existing list against a desired list of masks.
+///
+/// Returns `(to_add, to_remove)`: configured masks not already present are
+/// added, and existing masks that a configured mask covers (and which aren't
+/// themselves configured verbatim) are removed as redundant.
+pub fn reconcile(
+    desired: &HashSet<String>,
+    existing: &HashSet<String>,
+) -> (Vec<String>, Vec<String>) {
+    let masks: Vec<HostMask> = desired.iter().map(|m| HostMask::parse(m)).collect();
+    let mut add: Vec<String> = desired
+        .iter()
+        .filter(|want| !existing.contains(*want))
+        .cloned()
+        .collect();
+    add.sort();
+    let mut remove: Vec<String> = existing
+        .iter()
+        .filter(|have| !desired.contains(*have))
+        .filter(|have| {
+            let parsed = HostMask::parse(have);
+            masks.iter().any(|m| m.covers(&parsed))
+        })
+        .cloned()
+        .collect();
+    remove.sort();
+    (add, remove)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask(input: &str) -> HostMask {
+        HostMask::parse(input)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            mask("nick!user@host"),
+            HostMask {
+                nick: "nick".to_string(),
+                user: "user".to_string(),
+                host: "host".to_string(),
+            }
+        );
+        // Libera extban-style cloaks parse like any other mask
+        assert_eq!(
+            mask("*!*@libera/staff/*"),
+            HostMask {
+                nick: "*".to_string(),
+                user: "*".to_string(),
+                host: "libera/staff/*".to_string(),
+            }
+        );
+        // Missing components become "*"
+        assert_eq!(mask("badhost"), mask("*!*@badhost"));
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("*", "anything"));
+        assert!(glob("a*c", "abc"));
+        assert!(glob("a*c", "ac"));
+        assert!(glob("a?c", "abc"));
+        assert!(!glob("a?c", "ac"));
+        assert!(!glob("a*c", "abd"));
+    }
+
+    #[test]
+    fn test_covers() {
+        // A wildcard cloak covers a specific nick on the same host
+        assert!(mask("*!*@evil.example").covers(&mask("bad!~b@evil.example")));
+        // Host matching is case-insensitive
+        assert!(mask("*!*@Libera/Staff/*").covers(&mask("x!y@libera/staff/foo")));
+        // But nick/user are not
+        assert!(!mask("Bad!*@*").covers(&mask("bad!x@host")));
+        // Disjoint hosts don't cover
+        assert!(!mask("*!*@good.example").covers(&mask("x!y@evil.example")));
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let desired: HashSet<String> =
+            ["*!*@evil.example".to_string()].iter().cloned().collect();
+        let existing: HashSet<String> = [
+            "bad!~b@evil.example".to_string(),
+            "keep!~k@good.example".to_string(),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let (add, remove) = reconcile(&desired, &existing);
+        assert_eq!(add, vec!["*!*@evil.example".to_string()]);
+        // The specific ban is redundant; the unrelated one is left alone
+        assert_eq!(remove, vec!["bad!~b@evil.example".to_string()]);
+    }
+}